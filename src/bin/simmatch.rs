@@ -4,12 +4,17 @@
 //! definitions (i.e., different netlist).
 //! It outputs the signal pairs that are likely the same logic.
 
-use simalign::{ HashDB, HId };
+use simalign::{ HashDB, HId, mmap_merge_join };
 use ciborium::from_reader;
 use std::fs::File;
 use std::io::BufReader;
+use std::collections::HashSet;
 use indexmap::IndexMap;
 use itertools::Itertools;
+use compact_str::CompactString;
+use std::fmt;
+
+type Name = (Vec<CompactString>, Option<i32>);
 
 #[derive(clap::Parser, Debug)]
 struct SimMatchArgs {
@@ -22,35 +27,238 @@ struct SimMatchArgs {
     /// If a matched group has size larger than this value,
     /// it will be ignored.
     #[clap(default_value_t = 30)]
-    ignore_size: usize
+    ignore_size: usize,
+    /// Number of LSH bands to split the MinHash signature into.
+    /// Must divide the signature length; ignored if MinHash is
+    /// disabled for the given databases.
+    #[clap(long, default_value_t = 32)]
+    minhash_bands: usize,
+    /// Minimum estimated Jaccard similarity for a MinHash candidate
+    /// pair to be reported.
+    #[clap(long, default_value_t = 0.5)]
+    minhash_threshold: f64
+}
+
+/// Fold a band of MinHash signature words into a single bucket key.
+/// Two bits land in the same LSH bucket for a band iff every word in
+/// that band matches exactly.
+#[inline]
+fn band_hash(words: &[u64]) -> u64 {
+    words.iter().fold(0xcbf29ce484222325u64, |acc, &w|
+        (acc ^ w).wrapping_mul(0x100000001b3))
+}
+
+/// Estimate the Jaccard similarity of two bits from their MinHash
+/// signatures: the fraction of positions where both agree.
+#[inline]
+fn minhash_similarity(sig1: &[u64], sig2: &[u64]) -> f64 {
+    let agree = sig1.iter().zip(sig2.iter())
+        .filter(|(a, b)| a == b)
+        .count();
+    agree as f64 / sig1.len() as f64
+}
+
+/// The largest divisor of `k` that is at most `b`, suggested to the
+/// user when `--minhash-bands` doesn't evenly divide the signature
+/// length. `k` is always positive here (MinHash is only in play when
+/// `minhash_seeds` is non-empty).
+fn largest_divisor_at_most(k: usize, b: usize) -> usize {
+    (1..=b).rev().find(|d| k % d == 0).unwrap_or(1)
+}
+
+/// Build a reverse map from hash-vector index to the names aliased
+/// to it, so candidate pairs found by index can be reported by name.
+fn id2names(db: &HashDB) -> Vec<Vec<&Name>> {
+    let mut id2names = vec![Vec::new(); db.hashes.len()];
+    for (name, &p) in db.name2id.iter() {
+        id2names[p].push(name);
+    }
+    id2names
+}
+
+/// Declared width (number of bits) of every hierarchical name, i.e.
+/// every distinct `HId.0` prefix.
+fn bus_widths(db: &HashDB) -> IndexMap<&[CompactString], usize> {
+    let mut widths = IndexMap::new();
+    for (hier, _) in db.name2id.keys() {
+        *widths.entry(hier.as_slice()).or_insert(0) += 1;
+    }
+    widths
+}
+
+/// One bit-to-bit correspondence discovered between a bus in db1 and
+/// a bus in db2.
+struct BusAlignment<'a> {
+    hier1: &'a [CompactString],
+    hier2: &'a [CompactString],
+    width1: usize,
+    width2: usize,
+    /// (bit index in bus1, bit index in bus2, whether inverted),
+    /// sorted by bit index in bus1.
+    bits: Vec<(Option<i32>, Option<i32>, bool)>
+}
+
+/// Regroup unambiguous scalar matches by their shared hierarchical
+/// prefix and solve the bit-to-bit correspondence between whole
+/// buses, so a matched N-bit bus reports as one alignment (with its
+/// index permutation and inversion mask) instead of N scalar lines.
+fn reconstruct_buses<'a>(
+    db1: &'a HashDB, db2: &'a HashDB,
+    pool: &'a IndexMap<u128, (Vec<HId>, Vec<HId>)>,
+    invpool: &'a IndexMap<u128, (Vec<HId>, Vec<HId>)>
+) -> Vec<BusAlignment<'a>> {
+    let widths1 = bus_widths(db1);
+    let widths2 = bus_widths(db2);
+    let mut groups = IndexMap::<
+        (&'a [CompactString], &'a [CompactString]),
+        Vec<(Option<i32>, Option<i32>, bool)>
+    >::new();
+    for (inverted, p) in [(false, pool), (true, invpool)] {
+        for (v1, v2) in p.values() {
+            // only unambiguous bit-level matches contribute to a
+            // bus's bit-to-bit correspondence.
+            if v1.len() == 1 && v2.len() == 1 {
+                let (hier1, idx1) = (v1[0].0.as_slice(), v1[0].1);
+                let (hier2, idx2) = (v2[0].0.as_slice(), v2[0].1);
+                groups.entry((hier1, hier2)).or_default()
+                    .push((idx1, idx2, inverted));
+            }
+        }
+    }
+    groups.into_iter()
+        .filter(|(_, bits)| bits.len() > 1)
+        .map(|((hier1, hier2), mut bits)| {
+            bits.sort_by_key(|&(idx1, _, _)| idx1);
+            BusAlignment {
+                hier1, hier2,
+                width1: widths1[hier1], width2: widths2[hier2],
+                bits
+            }
+        })
+        .collect()
+}
+
+impl fmt::Display for BusAlignment<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let perm = self.bits.iter().map(|&(idx1, idx2, inv)| {
+            format!("{}->{}{}",
+                    idx1.map_or("_".to_string(), |i| i.to_string()),
+                    idx2.map_or("_".to_string(), |i| i.to_string()),
+                    if inv { "'" } else { "" })
+        }).format(", ");
+        // a strictly descending idx2 sequence (as idx1 rises) is the
+        // common MSB/LSB-swapped bus case.
+        let reversed = self.bits.windows(2)
+            .all(|w| match (w[0].1, w[1].1) {
+                (Some(a), Some(b)) => a > b,
+                _ => false
+            }) && self.bits.len() > 1;
+        write!(f, "Bus {} [{}/{} bits] = {} [{}/{} bits]: {}{}",
+               self.hier1.iter().format("/"), self.bits.len(), self.width1,
+               self.hier2.iter().format("/"), self.bits.len(), self.width2,
+               perm,
+               if reversed { " (bit-reversed)" } else { "" })
+    }
+}
+
+/// Match two huge databases without loading either fully into RAM,
+/// by streaming a merge-join over their sorted mmap backends. Only
+/// exact fingerprint matches are reported; the richer MinHash/bus
+/// analyses need the in-memory backend.
+fn run_mmap(args: &SimMatchArgs) {
+    let db1 = HashDB::open_mmap(&args.db1).unwrap();
+    let db2 = HashDB::open_mmap(&args.db2).unwrap();
+    if db1.hasher_kind() != db2.hasher_kind() {
+        clilog::error!(
+            SIMAL_HASHER_MISMATCH,
+            "db1 and db2 were built with different hasher kinds \
+             ({:?} vs {:?}); their fingerprints are not comparable",
+            db1.hasher_kind(), db2.hasher_kind()
+        );
+        std::process::exit(1);
+    }
+    if db1.value_canon() != db2.value_canon() || db1.glitch_settle() != db2.glitch_settle() {
+        clilog::error!(
+            SIMAL_CANON_MISMATCH,
+            "db1 and db2 were built with incompatible value \
+             canonicalization settings (value_canon {:?} vs {:?}, \
+             glitch_settle {} vs {}); their fingerprints are not comparable",
+            db1.value_canon(), db2.value_canon(),
+            db1.glitch_settle(), db2.glitch_settle()
+        );
+        std::process::exit(1);
+    }
+    let mut total = 0usize;
+    mmap_merge_join(&db1, &db2, |h, names1, names2| {
+        total += 1;
+        if names1.len() <= args.ignore_size && names2.len() <= args.ignore_size {
+            println!("Hash {}: {{ {} }} = {{ {} }}",
+                     h, names1.iter().format(", "), names2.iter().format(", "));
+        }
+    });
+    println!("matched bit types: {}", total);
 }
 
 fn main() {
     clilog::init_stderr_color_debug();
     let args = <SimMatchArgs as clap::Parser>::parse();
     println!("args: {:#?}", args);
+    if args.db1.ends_with(".mmdb") || args.db2.ends_with(".mmdb") {
+        return run_mmap(&args);
+    }
     let db1: HashDB = from_reader(
         BufReader::new(File::open(args.db1).unwrap())
     ).unwrap();
     let db2: HashDB = from_reader(
         BufReader::new(File::open(args.db2).unwrap())
     ).unwrap();
-    let mut pool = IndexMap::<u64, (Vec<&HId>, Vec<&HId>)>::new();
-    macro_rules! enum_db {
-        ($(($db:ident, $dbi:tt)),+) => ($(
-            for (hid, p) in $db.name2id.iter() {
+    if db1.hasher_kind != db2.hasher_kind {
+        clilog::error!(
+            SIMAL_HASHER_MISMATCH,
+            "db1 and db2 were built with different hasher kinds \
+             ({:?} vs {:?}); their fingerprints are not comparable",
+            db1.hasher_kind, db2.hasher_kind
+        );
+        std::process::exit(1);
+    }
+    if db1.value_canon != db2.value_canon || db1.glitch_settle != db2.glitch_settle {
+        clilog::error!(
+            SIMAL_CANON_MISMATCH,
+            "db1 and db2 were built with incompatible value \
+             canonicalization settings (value_canon {:?} vs {:?}, \
+             glitch_settle {} vs {}); their fingerprints are not comparable",
+            db1.value_canon, db2.value_canon,
+            db1.glitch_settle, db2.glitch_settle
+        );
+        std::process::exit(1);
+    }
+    // `pool` groups names by their direct fingerprint; `invpool`
+    // groups db1's names against db2's *inverted* fingerprints, so a
+    // bit that was polarity-swapped between the two netlists still
+    // shows up as a match.
+    let mut pool = IndexMap::<u128, (Vec<HId>, Vec<HId>)>::new();
+    let mut invpool = IndexMap::<u128, (Vec<HId>, Vec<HId>)>::new();
+    macro_rules! enum_side {
+        ($map:ident, $db:ident, $hashes:ident, $dbi:tt, $require_switch:expr) => {
+            for (hid, &p) in $db.name2id.iter() {
+                // a bit that never switched has `hashes_inv == hashes`
+                // (nothing was ever folded into the inverted
+                // fingerprint), so it carries no real polarity
+                // information; skip it where that distinction matters.
+                if $require_switch && $db.hashes[p] == $db.hashes_inv[p] { continue; }
                 use indexmap::map::Entry::*;
-                let vs = match pool.entry($db.hashes[*p]) {
+                let vs = match $map.entry($db.$hashes[p]) {
                     Occupied(o) => o.into_mut(),
                     Vacant(v) => v.insert(Default::default())
                 };
-                vs.$dbi.push(hid);
+                vs.$dbi.push(HId(hid.0.clone(), hid.1));
             }
-        )+)
-    }
-    enum_db! {
-        (db1, 0), (db2, 1)
+        }
     }
+    enum_side!(pool, db1, hashes, 0, false);
+    enum_side!(pool, db2, hashes, 1, false);
+    enum_side!(invpool, db1, hashes, 0, true);
+    enum_side!(invpool, db2, hashes_inv, 1, true);
     println!("total bit types: {}", pool.len());
     println!("matched bit types: {}", pool.values()
              .filter(|(v1, v2)| v1.len() != 0 && v2.len() != 0)
@@ -62,14 +270,114 @@ fn main() {
                      && v1.len() <= args.ignore_size
                      && v2.len() <= args.ignore_size)
              .count());
-    // print all matched bit types..
+
+    // try to recognize whole matched buses before falling back to
+    // scalar per-bit lines, so an N-bit bus reports as one alignment.
+    let bus_groups = reconstruct_buses(&db1, &db2, &pool, &invpool);
+    println!("matched buses ({}+ bits): {}", 2, bus_groups.len());
+    for bus in &bus_groups {
+        println!("{}", bus);
+    }
+    let bussed_hiers: HashSet<&[CompactString]> = bus_groups.iter()
+        .map(|bus| bus.hier1).collect();
+
+    // print all remaining (non-bussed) matched bit types..
     for (h, (v1, v2)) in pool.iter()
         .filter(|(_, (v1, v2))| v1.len() != 0 && v2.len() != 0
                 && v1.len() <= args.ignore_size
-                && v2.len() <= args.ignore_size)
+                && v2.len() <= args.ignore_size
+                && !(v1.len() == 1 && bussed_hiers.contains(v1[0].0.as_slice())))
     {
         println!("Hash {}: {{ {} }} = {{ {} }}",
                  h, v1.iter().format(", "), v2.iter().format(", "));
     }
+    // ..and any remaining polarity-inverted matches (e.g. an
+    // active-low reset renamed to active-high) that didn't get
+    // absorbed into a bus alignment above, so they're not silently
+    // dropped from the report.
+    for (h, (v1, v2)) in invpool.iter()
+        .filter(|(_, (v1, v2))| v1.len() != 0 && v2.len() != 0
+                && v1.len() <= args.ignore_size
+                && v2.len() <= args.ignore_size
+                && !(v1.len() == 1 && bussed_hiers.contains(v1[0].0.as_slice())))
+    {
+        println!("Hash {} (inverted): {{ {} }} = {{ {} }}'",
+                 h, v1.iter().format(", "), v2.iter().format(", "));
+    }
+
+    // approximate matching via MinHash + LSH banding, for signals
+    // whose exact fingerprint diverges by a few strobes.
+    if !db1.minhash_seeds.is_empty() || !db2.minhash_seeds.is_empty() {
+        if db1.minhash_seeds != db2.minhash_seeds {
+            clilog::error!(
+                SIMAL_MINHASH_MISMATCH,
+                "db1 and db2 were built with different MinHash seeds; \
+                 their sketches are not comparable"
+            );
+            std::process::exit(1);
+        }
+        let k = db1.minhash_seeds.len();
+        let b = args.minhash_bands.clamp(1, k);
+        if k % b != 0 {
+            clilog::error!(
+                SIMAL_MINHASH_BANDS_INDIVISIBLE,
+                "--minhash-bands {} does not divide the MinHash \
+                 signature length {}; the {} trailing word(s) per \
+                 signature would be silently excluded from every \
+                 band. Pick a divisor of {} (e.g. {})",
+                b, k, k % b, k, largest_divisor_at_most(k, b)
+            );
+            std::process::exit(1);
+        }
+        let r = k / b;
+        let id2names1 = id2names(&db1);
+        let id2names2 = id2names(&db2);
+        let sig_of = |db: &HashDB, p: usize| &db.minhash_sigs[p * k..(p + 1) * k];
+
+        let mut candidates = HashSet::<(usize, usize)>::new();
+        for bnd in 0..b {
+            let words = bnd * r..bnd * r + r;
+            let mut buckets = IndexMap::<u64, (Vec<usize>, Vec<usize>)>::new();
+            for p1 in 0..db1.hashes.len() {
+                // a bit that never switched keeps its MinHash
+                // signature at the sentinel `u64::MAX`; it carries no
+                // switching information to match on, and bucketing it
+                // would collide every quiescent bit together, turning
+                // the LSH candidate search back into an O(N^2) scan.
+                if sig_of(&db1, p1).iter().any(|&w| w == u64::MAX) { continue; }
+                buckets.entry(band_hash(&sig_of(&db1, p1)[words.clone()]))
+                    .or_default().0.push(p1);
+            }
+            for p2 in 0..db2.hashes.len() {
+                if sig_of(&db2, p2).iter().any(|&w| w == u64::MAX) { continue; }
+                buckets.entry(band_hash(&sig_of(&db2, p2)[words.clone()]))
+                    .or_default().1.push(p2);
+            }
+            for (ps1, ps2) in buckets.values() {
+                for &p1 in ps1 {
+                    for &p2 in ps2 {
+                        candidates.insert((p1, p2));
+                    }
+                }
+            }
+        }
+
+        let mut ranked: Vec<_> = candidates.into_iter()
+            .filter(|&(p1, p2)| db1.hashes[p1] != db2.hashes[p2])
+            .map(|(p1, p2)|
+                 (minhash_similarity(sig_of(&db1, p1), sig_of(&db2, p2)), p1, p2))
+            .filter(|&(sim, _, _)| sim >= args.minhash_threshold)
+            .collect();
+        ranked.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+
+        println!("minhash candidate pairs (threshold {}): {}",
+                 args.minhash_threshold, ranked.len());
+        for (sim, p1, p2) in ranked {
+            println!("MinHash {:.4}: {{ {} }} ~ {{ {} }}",
+                     sim,
+                     id2names1[p1].iter().format(", "),
+                     id2names2[p2].iter().format(", "));
+        }
+    }
 }
 
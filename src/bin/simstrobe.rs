@@ -9,10 +9,45 @@
 //!
 //! The hash database is later used to align two netlists.
 
-use simalign::HashDB;
+use simalign::{ HashDB, HashDBConfig, HasherKind, ValueCanon };
 use ciborium::{ from_reader, into_writer };
 use std::fs::File;
 
+/// The hashing scheme to use when creating a new database.
+/// Ignored (the loaded database's own kind applies) when `db_input`
+/// is given.
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+enum HasherKindArg {
+    /// The fast 64-bit polynomial, replicated into two lanes.
+    Poly,
+    /// A keyed SipHash-1-3 in its native 128-bit mode. Requires
+    /// `--sip-k0`/`--sip-k1`.
+    Sip13,
+}
+
+/// How raw VCD values (`x`/`z`/...) are canonicalized when creating
+/// a new database. Ignored when `db_input` is given.
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+enum ValueCanonArg {
+    /// Keep the raw VCD value; `x`/`z` are distinct states.
+    Raw,
+    /// Merge every non-`0`/`1` value into one "unknown" state.
+    MergeUnknown,
+    /// Treat non-`0`/`1` values as a wildcard, ignoring that strobe.
+    WildcardUnknown,
+}
+
+impl From<ValueCanonArg> for ValueCanon {
+    #[inline]
+    fn from(a: ValueCanonArg) -> ValueCanon {
+        match a {
+            ValueCanonArg::Raw => ValueCanon::Raw,
+            ValueCanonArg::MergeUnknown => ValueCanon::MergeUnknown,
+            ValueCanonArg::WildcardUnknown => ValueCanon::WildcardUnknown
+        }
+    }
+}
+
 #[derive(clap::Parser, Debug)]
 struct SimStrobeArgs {
     /// The input vcd file path
@@ -28,6 +63,31 @@ struct SimStrobeArgs {
     /// If not specified, a new one will be created.
     #[clap(long)]
     db_input: Option<String>,
+    /// The hasher kind for a newly-created database.
+    #[clap(long, value_enum, default_value = "poly")]
+    hasher_kind: HasherKindArg,
+    /// First 64-bit key, required when `hasher_kind` is `sip13`.
+    #[clap(long)]
+    sip_k0: Option<u64>,
+    /// Second 64-bit key, required when `hasher_kind` is `sip13`.
+    #[clap(long)]
+    sip_k1: Option<u64>,
+    /// Number of MinHash functions to maintain per bit, for
+    /// approximate matching in `sim-match`. 0 disables MinHash.
+    /// Ignored when `db_input` is given.
+    #[clap(long, default_value_t = 0)]
+    minhash_k: usize,
+    /// Root seed the MinHash per-function seeds are derived from.
+    /// Two databases must use the same seed to be comparable.
+    #[clap(long, default_value_t = 0x5a5a5a5a5a5a5a5a)]
+    minhash_seed: u64,
+    /// How to canonicalize raw VCD values.
+    #[clap(long, value_enum, default_value = "raw")]
+    value_canon: ValueCanonArg,
+    /// Glitch filter: minimum number of strobe periods a switch must
+    /// hold before it is accepted. 0 disables filtering.
+    #[clap(long, default_value_t = 0)]
+    glitch_settle: u64,
 }
 
 fn main() {
@@ -38,12 +98,38 @@ fn main() {
         Some(dbpath) => from_reader(
             File::open(dbpath).unwrap()
         ).unwrap(),
-        None => HashDB::new()
+        None => {
+            let hasher_kind = match args.hasher_kind {
+                HasherKindArg::Poly => HasherKind::Poly,
+                HasherKindArg::Sip13 => HasherKind::Sip13 {
+                    k0: args.sip_k0.expect("--sip-k0 is required for --hasher-kind sip13"),
+                    k1: args.sip_k1.expect("--sip-k1 is required for --hasher-kind sip13")
+                }
+            };
+            HashDB::with_config(HashDBConfig {
+                hasher_kind,
+                minhash_k: args.minhash_k,
+                minhash_seed: args.minhash_seed,
+                value_canon: args.value_canon.into(),
+                glitch_settle: args.glitch_settle
+            })
+        }
     };
     db.feed_vcd(&args.vcd, args.strobe_start, args.strobe_period)
         .unwrap();
-    into_writer(
-        &db,
-        File::create(&args.db_output).unwrap()
-    ).unwrap();
+    // the sorted mmap backend is read-only and keeps only the
+    // fingerprints, the names and the compatibility settings (no
+    // inverse fingerprints or MinHash sketches), so it's only offered
+    // as an output format, chosen by
+    // file extension; `--db-input` always reads the mutable CBOR
+    // format so a database can keep being fed more VCDs.
+    if args.db_output.ends_with(".mmdb") {
+        db.save_mmap(&args.db_output).unwrap();
+    }
+    else {
+        into_writer(
+            &db,
+            File::create(&args.db_output).unwrap()
+        ).unwrap();
+    }
 }
@@ -9,11 +9,17 @@ use indexmap::IndexMap;
 use compact_str::CompactString;
 use serde::{ Serialize, Deserialize };
 use vcd_ng::{ Parser, FastFlow, ReferenceIndex, Var, ScopeItem, FastFlowToken, FFValueChange };
+use siphasher::sip128::{ Hasher128, SipHasher13 };
 use std::fs::File;
 use std::io::{ self, BufReader };
 use std::hash::{ Hash, Hasher };
 use std::borrow::Borrow;
 
+mod hid;
+pub use hid::{ HId, RefHId };
+mod mmap;
+pub use mmap::{ MmapHashDb, merge_join as mmap_merge_join };
+
 /// A general hier name with index, used as hashing.
 trait HierNameIdx {
     fn hier(&self) -> &[CompactString];
@@ -67,6 +73,151 @@ impl HierNameIdx for (&Vec<CompactString>, Option<i32>) {
     }
 }
 
+/// Selects the hash construction used to fingerprint each bit's
+/// switching history.
+///
+/// Two `HashDB`s can only be meaningfully compared (e.g. in
+/// `sim-match`) if they were built with the same `HasherKind`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HasherKind {
+    /// The original fast 64-bit polynomial, replicated into two
+    /// lanes with different multipliers to make a 128-bit fingerprint.
+    Poly,
+    /// A keyed SipHash-1-3 run in its native 128-bit mode.
+    /// The keys are recorded here so a `HashDB` is self-contained.
+    Sip13 { k0: u64, k1: u64 },
+}
+
+impl HasherKind {
+    #[inline]
+    fn new_hasher(self) -> BitHasher {
+        match self {
+            HasherKind::Poly => BitHasher::Poly(PolyHasher::default()),
+            HasherKind::Sip13 { k0, k1 } =>
+                BitHasher::Sip13(SipHasher13::new_with_keys(k0, k1))
+        }
+    }
+}
+
+/// The original polynomial hash, widened to two `u64` lanes so it
+/// can produce a 128-bit fingerprint like any other `HasherKind`.
+#[derive(Debug, Default, Copy, Clone)]
+struct PolyHasher {
+    lo: u64,
+    hi: u64
+}
+
+impl PolyHasher {
+    #[inline]
+    fn finish128(&self) -> u128 {
+        ((self.hi as u128) << 64) | self.lo as u128
+    }
+}
+
+impl Hasher for PolyHasher {
+    #[inline]
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.lo = self.lo.wrapping_mul(80267270009u64)
+                .wrapping_add(b as u64 + 1);
+            self.hi = self.hi.wrapping_mul(16777619u64)
+                .wrapping_add(b as u64 + 1);
+        }
+    }
+
+    #[inline]
+    fn finish(&self) -> u64 {
+        self.lo
+    }
+}
+
+/// One live hasher accumulating a single bit's switching history
+/// during a `feed_vcd` call, dispatched on `HasherKind`.
+enum BitHasher {
+    Poly(PolyHasher),
+    Sip13(SipHasher13)
+}
+
+impl BitHasher {
+    /// Write an accepted switch (the period it happened in, and the
+    /// state switched to) into the hasher.
+    #[inline]
+    fn write_switch(&mut self, last_index: u64, cur_state: u8) {
+        match self {
+            BitHasher::Poly(h) => {
+                h.write_u64(last_index);
+                h.write_u8(cur_state);
+            },
+            BitHasher::Sip13(h) => {
+                h.write_u64(last_index);
+                h.write_u8(cur_state);
+            }
+        }
+    }
+
+    /// Write a single domain-separator byte, e.g. between VCD files.
+    #[inline]
+    fn write_separator(&mut self, b: u8) {
+        match self {
+            BitHasher::Poly(h) => h.write_u8(b),
+            BitHasher::Sip13(h) => h.write_u8(b)
+        }
+    }
+
+    /// Write the fingerprint carried over from previously-fed files,
+    /// so switching history accumulates across multiple `feed_vcd`
+    /// calls on the same database.
+    #[inline]
+    fn write_carry(&mut self, carry: u128) {
+        match self {
+            BitHasher::Poly(h) => h.write_u128(carry),
+            BitHasher::Sip13(h) => h.write_u128(carry)
+        }
+    }
+
+    #[inline]
+    fn finish128(&self) -> u128 {
+        match self {
+            BitHasher::Poly(h) => h.finish128(),
+            BitHasher::Sip13(h) => {
+                let h = h.finish128();
+                ((h.h1 as u128) << 64) | h.h2 as u128
+            }
+        }
+    }
+}
+
+/// How raw VCD values are canonicalized into the state `BitState`
+/// tracks, so hashes built from an RTL run (few X's) and a
+/// gate-level run (many X's, e.g. during reset) of the same logic
+/// can still agree.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueCanon {
+    /// Use the raw VCD value verbatim; `x`/`z` are distinct states
+    /// like any other.
+    Raw,
+    /// Map every non-`0`/`1` value (`x`, `z`, ...) to a single
+    /// "unknown" state.
+    MergeUnknown,
+    /// Treat non-`0`/`1` values as a wildcard: the strobe is ignored
+    /// entirely, so it neither confirms nor breaks a switch.
+    WildcardUnknown
+}
+
+impl ValueCanon {
+    /// Canonicalize one raw VCD value, or return `None` if it should
+    /// be ignored entirely (`WildcardUnknown` on an unknown value).
+    #[inline]
+    fn apply(self, raw: u8) -> Option<u8> {
+        let unknown = raw > 1;
+        match self {
+            ValueCanon::Raw => Some(raw),
+            ValueCanon::MergeUnknown => Some(if unknown { 2 } else { raw }),
+            ValueCanon::WildcardUnknown => if unknown { None } else { Some(raw) }
+        }
+    }
+}
+
 /// The hash database.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct HashDB {
@@ -75,33 +226,96 @@ pub struct HashDB {
     /// Vector bits will be mapped separately like
     /// (xxx, 0) -> (start, 1), regardless of the declaration.
     pub name2id: IndexMap<(Vec<CompactString>, Option<i32>), usize>,
-    /// The flattened hash values.
-    pub hashes: Vec<u64>,
+    /// The flattened hash values, each a 128-bit fingerprint of one
+    /// bit's switching history.
+    pub hashes: Vec<u128>,
+    /// The fingerprint of each bit's logical inverse, computed from
+    /// the same switch events with the state flipped. Lets
+    /// `sim-match` recognize a bus bit that was inverted between the
+    /// two netlists by matching it against the non-inverted bit.
+    pub hashes_inv: Vec<u128>,
+    /// The hashing scheme `hashes` was produced with.
+    pub hasher_kind: HasherKind,
+    /// The per-function seeds of the MinHash sketch, or empty if
+    /// MinHash is disabled for this database.
+    ///
+    /// `minhash_sigs` is flattened the same way as `hashes`: bit `i`
+    /// owns the slice `minhash_sigs[i * minhash_seeds.len() ..]
+    /// [..minhash_seeds.len()]`.
+    pub minhash_seeds: Vec<u64>,
+    /// The flattened MinHash signatures, `minhash_seeds.len()` words
+    /// per bit. A word is `u64::MAX` if the bit never switched.
+    pub minhash_sigs: Vec<u64>,
+    /// How raw VCD values were canonicalized while building this
+    /// database.
+    pub value_canon: ValueCanon,
+    /// The minimum number of strobe periods a switch must hold before
+    /// it is accepted; a switch reverted sooner than this is
+    /// discarded as a glitch instead of being folded into the
+    /// fingerprint. 0 disables filtering.
+    pub glitch_settle: u64
 }
 
 /// The internal bit state
 #[derive(Debug, Copy, Clone)]
 struct BitState {
-    /// The last switched period index (after).
+    /// The period index the pending candidate began at (0 meaning no
+    /// candidate has been observed yet).
     last_index: u64,
-    /// The state of last valid switch.
+    /// The currently committed (debounced) state.
     last_state: u8,
-    /// The current state (valid switch candidate).
+    /// The canonicalized value sampled since `last_index`; only promoted to
+    /// `last_state` once it survives the glitch filter.
     cur_state: u8
 }
 
 impl BitState {
-    /// update the hash if a switch happens.
+    /// whether a switch candidate is pending, regardless of the
+    /// glitch filter.
     #[inline]
-    fn update_hash(&self, h: &mut u64) {
-        if self.last_index != 0 &&
-            self.last_state != self.cur_state
-        {
-            // a switch happens on last_index.
-            *h = h.wrapping_mul(80267270009u64)
-                .wrapping_add(self.last_index)
-                .wrapping_mul(257u64)
-                .wrapping_add(self.cur_state as u64 + 1);
+    fn is_switch(&self) -> bool {
+        self.last_index != 0 && self.last_state != self.cur_state
+    }
+
+    /// whether the pending switch has held continuously from
+    /// `last_index` through `next_index` for at least `glitch_settle`
+    /// periods, i.e. it is a genuine debounced transition rather than
+    /// a transient glitch reverted before settling. A switch that is
+    /// still pending when the file ends (no `next_index` to check
+    /// against) is always genuine: it never got the chance to revert.
+    #[inline]
+    fn survives_glitch_filter(&self, next_index: u64, glitch_settle: u64) -> bool {
+        next_index - self.last_index >= glitch_settle
+    }
+
+    /// promote the pending switch to the committed state once it has
+    /// survived the glitch filter.
+    #[inline]
+    fn accept(&mut self) {
+        self.last_state = self.cur_state;
+    }
+
+    /// fold the pending switch into the running fingerprint.
+    #[inline]
+    fn write_hash(&self, h: &mut BitHasher) {
+        h.write_switch(self.last_index, self.cur_state + 1);
+    }
+
+    /// fold the pending switch into the running fingerprint of this
+    /// bit's logical inverse, so an inverted copy of a signal still
+    /// fingerprints identically to the original.
+    #[inline]
+    fn write_hash_inv(&self, h: &mut BitHasher) {
+        h.write_switch(self.last_index, invert_state(self.cur_state) + 1);
+    }
+
+    /// fold the pending switch into the MinHash sketch `sig`, one
+    /// word per seed in `seeds`.
+    #[inline]
+    fn write_minhash(&self, seeds: &[u64], sig: &mut [u64]) {
+        for (seed, word) in seeds.iter().zip(sig.iter_mut()) {
+            let v = minhash_event(*seed, self.last_index, self.cur_state + 1);
+            *word = (*word).min(v);
         }
     }
 }
@@ -167,13 +381,91 @@ fn enumerate_bits(index: Option<ReferenceIndex>, f: &mut impl FnMut(Option<i32>,
     }
 }
 
+/// The SplitMix64 mixing step, used both to derive the MinHash
+/// per-function seeds from a single root seed, and as one of the
+/// hash functions themselves.
+#[inline]
+fn splitmix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9E3779B97F4A7C15);
+    let mut z = x;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+/// Flip a canonicalized bit state for the logical-inverse fingerprint:
+/// `0`/`1` swap, any other (unknown) state is left as-is.
+#[inline]
+fn invert_state(s: u8) -> u8 {
+    if s <= 1 { 1 - s } else { s }
+}
+
+/// Evaluate the `seed`-th MinHash function on a switching event
+/// `(strobe_index, state)`.
+#[inline]
+fn minhash_event(seed: u64, strobe_index: u64, state: u8) -> u64 {
+    splitmix64(splitmix64(seed ^ strobe_index) ^ state as u64)
+}
+
+/// The knobs that are fixed for a `HashDB`'s whole lifetime, settable
+/// only at creation. Two databases must agree on all of these to be
+/// comparable in `sim-match`.
+#[derive(Debug, Clone, Copy)]
+pub struct HashDBConfig {
+    /// The hashing scheme for `hashes`.
+    pub hasher_kind: HasherKind,
+    /// Number of MinHash functions to maintain per bit, 0 to
+    /// disable MinHash sketches.
+    pub minhash_k: usize,
+    /// Root seed the MinHash per-function seeds are derived from.
+    pub minhash_seed: u64,
+    /// How raw VCD values are canonicalized.
+    pub value_canon: ValueCanon,
+    /// The glitch filter's debounce threshold: the minimum number of
+    /// strobe periods a switch must hold before it is accepted.
+    pub glitch_settle: u64
+}
+
+impl Default for HashDBConfig {
+    #[inline]
+    fn default() -> HashDBConfig {
+        HashDBConfig {
+            hasher_kind: HasherKind::Poly,
+            minhash_k: 0,
+            minhash_seed: 0,
+            value_canon: ValueCanon::Raw,
+            glitch_settle: 0
+        }
+    }
+}
+
 impl HashDB {
-    /// Create a new empty hash database.
+    /// Create a new empty hash database using the default
+    /// configuration (fast polynomial hasher, MinHash disabled, raw
+    /// value canonicalization, no glitch filtering).
     #[inline]
     pub fn new() -> HashDB {
+        HashDB::with_config(HashDBConfig::default())
+    }
+
+    /// Create a new empty hash database with an explicit
+    /// configuration.
+    pub fn with_config(config: HashDBConfig) -> HashDB {
+        let mut minhash_seeds = Vec::new();
+        let mut seed = config.minhash_seed;
+        for _ in 0..config.minhash_k {
+            seed = splitmix64(seed);
+            minhash_seeds.push(seed);
+        }
         HashDB {
             name2id: IndexMap::new(),
-            hashes: Vec::new()
+            hashes: Vec::new(),
+            hashes_inv: Vec::new(),
+            hasher_kind: config.hasher_kind,
+            minhash_seeds,
+            minhash_sigs: Vec::new(),
+            value_canon: config.value_canon,
+            glitch_settle: config.glitch_settle
         }
     }
 
@@ -236,7 +528,11 @@ impl HashDB {
                             usize::MAX => {
                                 let start = self.hashes.len();
                                 self.hashes.extend((0..var.size).map(|_| 0));
+                                self.hashes_inv.extend((0..var.size).map(|_| 0));
                                 hash_used.extend((0..var.size).map(|_| u64::MAX));
+                                let k = self.minhash_seeds.len();
+                                self.minhash_sigs.extend(
+                                    (0..var.size * k).map(|_| u64::MAX));
                                 start
                             },
                             idp @ _ => idp
@@ -287,12 +583,23 @@ impl HashDB {
         &mut self, vcd_file: &str,
         strobe_start: u64, strobe_period: u64
     ) -> io::Result<()> {
-        // insert into hashes the separator between vcd files
-        for v in self.hashes.iter_mut() {
-            *v *= 100003;
-        }
         // get hash indices
         let indices = self.make_vcd_metadata(vcd_file)?;
+        // one live hasher per bit, seeded with the fingerprint carried
+        // over from previous files (if any) and a domain-separator
+        // byte marking the start of this vcd file.
+        let mut hashers: Vec<BitHasher> = self.hashes.iter().map(|&carry| {
+            let mut h = self.hasher_kind.new_hasher();
+            h.write_carry(carry);
+            h.write_separator(0xff);
+            h
+        }).collect();
+        let mut hashers_inv: Vec<BitHasher> = self.hashes_inv.iter().map(|&carry| {
+            let mut h = self.hasher_kind.new_hasher();
+            h.write_carry(carry);
+            h.write_separator(0xff);
+            h
+        }).collect();
         // stream read signals and update hashes
         let f = File::open(vcd_file)?;
         let mut parser = FastFlow::new(f, 65536);
@@ -300,6 +607,7 @@ impl HashDB {
         let mut states = vec![BitState {
             last_index: 0, last_state: 0, cur_state: 0
         }; self.hashes.len()];
+        let k = self.minhash_seeds.len();
         while let Some(tok) = parser.next_token()? {
             match tok {
                 FastFlowToken::Timestamp(t) => {
@@ -309,25 +617,72 @@ impl HashDB {
                 },
                 FastFlowToken::Value(FFValueChange{ id, bits }) => {
                     let id_st = indices[id.0 as usize];
-                    for (i, &bit) in bits.iter().enumerate() {
+                    for (i, &raw_bit) in bits.iter().enumerate() {
+                        let bit = match self.value_canon.apply(raw_bit) {
+                            Some(bit) => bit,
+                            // unknown treated as wildcard: this
+                            // strobe neither confirms nor breaks a
+                            // switch, so just ignore it.
+                            None => continue
+                        };
                         let state = &mut states[id_st + i];
                         if state.last_index == cur_time_id {
                             state.cur_state = bit;
                         }
                         else {
-                            state.update_hash(&mut self.hashes[id_st + i]);
+                            // the pending candidate's duration is now
+                            // known (it held from last_index through
+                            // this new sample), so decide whether it
+                            // was a real transition or a glitch.
+                            if state.is_switch() &&
+                                state.survives_glitch_filter(cur_time_id, self.glitch_settle)
+                            {
+                                state.write_hash(&mut hashers[id_st + i]);
+                                state.write_hash_inv(&mut hashers_inv[id_st + i]);
+                                state.write_minhash(
+                                    &self.minhash_seeds,
+                                    &mut self.minhash_sigs[
+                                        (id_st + i) * k..(id_st + i + 1) * k]);
+                                state.accept();
+                            }
+                            else if state.last_index == 0 {
+                                // the value held before the first strobe
+                                // is the initial state, not a switch.
+                                state.accept();
+                            }
+                            // either way, a new candidate period
+                            // starts now; an unsettled candidate is
+                            // simply dropped, leaving `last_state`
+                            // (and thus the fingerprint) as if the
+                            // glitch never happened.
                             state.last_index = cur_time_id;
-                            state.last_state = state.cur_state;
                             state.cur_state = bit;
                         }
                     }
                 }
             }
         }
-        for (state, h) in states.iter().zip(
-            self.hashes.iter_mut()
-        ) {
-            state.update_hash(h);
+        for (i, ((state, h), h_inv)) in states.iter()
+            .zip(hashers.iter_mut())
+            .zip(hashers_inv.iter_mut())
+            .enumerate()
+        {
+            // a candidate still pending at end-of-file held all the
+            // way to the end without reverting, so it's a genuine
+            // transition regardless of `glitch_settle`.
+            if state.is_switch() {
+                state.write_hash(h);
+                state.write_hash_inv(h_inv);
+                state.write_minhash(
+                    &self.minhash_seeds,
+                    &mut self.minhash_sigs[i * k..(i + 1) * k]);
+            }
+        }
+        for (h, slot) in hashers.iter().zip(self.hashes.iter_mut()) {
+            *slot = h.finish128();
+        }
+        for (h, slot) in hashers_inv.iter().zip(self.hashes_inv.iter_mut()) {
+            *slot = h.finish128();
         }
         Ok(())
     }
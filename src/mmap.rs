@@ -0,0 +1,270 @@
+//! A memory-mapped, on-disk sorted backend for `HashDB`, for designs
+//! with too many signals to load the whole database into RAM.
+//!
+//! [`HashDB::save_mmap`](crate::HashDB::save_mmap) snapshots a
+//! `HashDB` into a flat, little-endian binary file; [`MmapHashDb`]
+//! maps that file back in read-only and binary-searches it directly
+//! on the mapped pages, never materializing the full index. The
+//! on-disk layout is:
+//!
+//! ```text
+//! magic:          [u8; 8]                b"SAMMDB01"
+//! n_hashes:       u64                    entries in hash_index
+//! n_names:        u64                    entries in name_index
+//! name_blob_len:  u64                    length of name_blob
+//! compat_len:     u64                    length of compat_blob
+//! compat_blob:    [u8; compat_len]       CBOR-encoded `MmapCompat`
+//! hash_index:     [(u128, u32); n_hashes]  sorted by hash ascending
+//! name_index:     [(u32, u64, u32); n_names]  sorted by id ascending;
+//!                                          (id, blob offset, blob len)
+//! name_blob:      [u8; name_blob_len]     UTF-8 "hier/path[idx]" names
+//! ```
+//!
+//! `hash_index` may list several ids under one hash (distinct bits
+//! that alias to the same fingerprint); `name_index` may list several
+//! names under one id (vector bit aliasing).
+
+use crate::{ HashDB, HasherKind, ValueCanon };
+use serde::{ Serialize, Deserialize };
+use memmap2::Mmap;
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{ self, BufWriter, Write };
+
+const MAGIC: &[u8; 8] = b"SAMMDB01";
+const HASH_ENTRY_SIZE: usize = 16 + 4;
+const NAME_ENTRY_SIZE: usize = 4 + 8 + 4;
+const HEADER_SIZE: usize = 8 + 8 + 8 + 8 + 8;
+
+fn bad_magic() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "not a SimAlign mmap database")
+}
+
+/// The subset of a `HashDB`'s build-time configuration that two
+/// `.mmdb` files must agree on to be meaningfully compared, persisted
+/// in the file header so `sim-match` can check compatibility without
+/// ever deserializing the full (in-memory) CBOR backend.
+#[derive(Serialize, Deserialize)]
+struct MmapCompat {
+    hasher_kind: HasherKind,
+    value_canon: ValueCanon,
+    glitch_settle: u64
+}
+
+/// A read-only, memory-mapped view over a `HashDB` written by
+/// [`HashDB::save_mmap`].
+pub struct MmapHashDb {
+    mmap: Mmap,
+    n_hashes: usize,
+    n_names: usize,
+    hash_index_off: usize,
+    name_index_off: usize,
+    name_blob_off: usize,
+    compat: MmapCompat
+}
+
+impl MmapHashDb {
+    /// Map `path` in read-only.
+    pub fn open(path: &str) -> io::Result<MmapHashDb> {
+        let f = File::open(path)?;
+        let mmap = unsafe { Mmap::map(&f)? };
+        if mmap.len() < HEADER_SIZE || &mmap[0..8] != MAGIC {
+            return Err(bad_magic());
+        }
+        let n_hashes = u64::from_le_bytes(mmap[8..16].try_into().unwrap()) as usize;
+        let n_names = u64::from_le_bytes(mmap[16..24].try_into().unwrap()) as usize;
+        let compat_len = u64::from_le_bytes(mmap[32..40].try_into().unwrap()) as usize;
+        let compat_off = HEADER_SIZE;
+        let compat: MmapCompat = ciborium::from_reader(&mmap[compat_off..compat_off + compat_len])
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let hash_index_off = compat_off + compat_len;
+        let name_index_off = hash_index_off + n_hashes * HASH_ENTRY_SIZE;
+        let name_blob_off = name_index_off + n_names * NAME_ENTRY_SIZE;
+        Ok(MmapHashDb {
+            mmap, n_hashes, n_names,
+            hash_index_off, name_index_off, name_blob_off,
+            compat
+        })
+    }
+
+    /// the hashing scheme this database was built with.
+    #[inline]
+    pub fn hasher_kind(&self) -> HasherKind {
+        self.compat.hasher_kind
+    }
+
+    /// how raw VCD values were canonicalized while building this
+    /// database.
+    #[inline]
+    pub fn value_canon(&self) -> ValueCanon {
+        self.compat.value_canon
+    }
+
+    /// the glitch filter's settling threshold this database was
+    /// built with.
+    #[inline]
+    pub fn glitch_settle(&self) -> u64 {
+        self.compat.glitch_settle
+    }
+
+    /// number of (hash, id) entries, sorted by hash.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.n_hashes
+    }
+
+    #[inline]
+    fn hash_entry(&self, i: usize) -> (u128, u32) {
+        let off = self.hash_index_off + i * HASH_ENTRY_SIZE;
+        let hash = u128::from_le_bytes(self.mmap[off..off + 16].try_into().unwrap());
+        let id = u32::from_le_bytes(self.mmap[off + 16..off + 20].try_into().unwrap());
+        (hash, id)
+    }
+
+    #[inline]
+    fn name_entry(&self, i: usize) -> (u32, u64, u32) {
+        let off = self.name_index_off + i * NAME_ENTRY_SIZE;
+        let id = u32::from_le_bytes(self.mmap[off..off + 4].try_into().unwrap());
+        let blob_off = u64::from_le_bytes(self.mmap[off + 4..off + 12].try_into().unwrap());
+        let blob_len = u32::from_le_bytes(self.mmap[off + 12..off + 16].try_into().unwrap());
+        (id, blob_off, blob_len)
+    }
+
+    /// the hash at sorted position `i`.
+    #[inline]
+    pub fn hash_at(&self, i: usize) -> u128 {
+        self.hash_entry(i).0
+    }
+
+    /// every name sharing the hash-id at sorted hash-position `i`.
+    pub fn names_at(&self, i: usize) -> Vec<&str> {
+        self.names_of(self.hash_entry(i).1)
+    }
+
+    /// every name sharing hash-id `id`, found by binary search over
+    /// the id-sorted name index.
+    pub fn names_of(&self, id: u32) -> Vec<&str> {
+        let lo = self.name_bound(id, Ordering::Less);
+        let hi = self.name_bound(id, Ordering::Greater);
+        (lo..hi).map(|i| {
+            let (_, off, len) = self.name_entry(i);
+            let off = self.name_blob_off + off as usize;
+            std::str::from_utf8(&self.mmap[off..off + len as usize]).unwrap()
+        }).collect()
+    }
+
+    /// a bound of the run of entries equal to `id`, selected by
+    /// `excluding`: with `Ordering::Less`, the first index whose id is
+    /// not less than `id` (lower bound); with `Ordering::Greater`, the
+    /// first index whose id is greater than `id` (upper bound).
+    fn name_bound(&self, id: u32, excluding: Ordering) -> usize {
+        let (mut lo, mut hi) = (0, self.n_names);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let ord = self.name_entry(mid).0.cmp(&id);
+            let past = match excluding {
+                Ordering::Less => ord != Ordering::Less,
+                _ => ord == Ordering::Greater
+            };
+            if past { hi = mid; }
+            else { lo = mid + 1; }
+        }
+        lo
+    }
+}
+
+impl HashDB {
+    /// Map a database written by [`HashDB::save_mmap`] back in
+    /// read-only, without loading it fully into RAM.
+    #[inline]
+    pub fn open_mmap(path: &str) -> io::Result<MmapHashDb> {
+        MmapHashDb::open(path)
+    }
+
+    /// Snapshot this database to the sorted, memory-mappable binary
+    /// format read by [`HashDB::open_mmap`].
+    pub fn save_mmap(&self, path: &str) -> io::Result<()> {
+        let mut hash_index: Vec<(u128, u32)> = self.hashes.iter()
+            .enumerate()
+            .map(|(id, &h)| (h, id as u32))
+            .collect();
+        hash_index.sort_unstable_by_key(|&(h, _)| h);
+
+        let mut name_index: Vec<(u32, String)> = self.name2id.iter()
+            .map(|((hier, idx), &id)| {
+                let mut s = hier.iter().map(|c| c.as_str()).collect::<Vec<_>>().join("/");
+                if let Some(i) = idx {
+                    s.push_str(&format!("[{}]", i));
+                }
+                (id as u32, s)
+            })
+            .collect();
+        name_index.sort_unstable_by_key(|(id, _)| *id);
+
+        let mut blob = Vec::new();
+        let mut name_records = Vec::with_capacity(name_index.len());
+        for (id, name) in &name_index {
+            let off = blob.len() as u64;
+            blob.extend_from_slice(name.as_bytes());
+            name_records.push((*id, off, name.len() as u32));
+        }
+
+        let mut compat_blob = Vec::new();
+        ciborium::into_writer(&MmapCompat {
+            hasher_kind: self.hasher_kind,
+            value_canon: self.value_canon,
+            glitch_settle: self.glitch_settle
+        }, &mut compat_blob).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let mut w = BufWriter::new(File::create(path)?);
+        w.write_all(MAGIC)?;
+        w.write_all(&(hash_index.len() as u64).to_le_bytes())?;
+        w.write_all(&(name_records.len() as u64).to_le_bytes())?;
+        w.write_all(&(blob.len() as u64).to_le_bytes())?;
+        w.write_all(&(compat_blob.len() as u64).to_le_bytes())?;
+        w.write_all(&compat_blob)?;
+        for (h, id) in &hash_index {
+            w.write_all(&h.to_le_bytes())?;
+            w.write_all(&id.to_le_bytes())?;
+        }
+        for (id, off, len) in &name_records {
+            w.write_all(&id.to_le_bytes())?;
+            w.write_all(&off.to_le_bytes())?;
+            w.write_all(&len.to_le_bytes())?;
+        }
+        w.write_all(&blob)?;
+        Ok(())
+    }
+}
+
+/// Stream a merge-join between two hash-sorted `MmapHashDb`s, calling
+/// `f` with the two name groups sharing every hash value present on
+/// both sides. Only the current group is ever materialized, so this
+/// scales to databases far larger than RAM.
+pub fn merge_join(
+    db1: &MmapHashDb, db2: &MmapHashDb,
+    mut f: impl FnMut(u128, &[&str], &[&str])
+) {
+    let (mut i, mut j) = (0, 0);
+    while i < db1.len() && j < db2.len() {
+        let h1 = db1.hash_at(i);
+        let h2 = db2.hash_at(j);
+        match h1.cmp(&h2) {
+            Ordering::Less => i += 1,
+            Ordering::Greater => j += 1,
+            Ordering::Equal => {
+                let i_end = (i..db1.len())
+                    .take_while(|&k| db1.hash_at(k) == h1).count() + i;
+                let j_end = (j..db2.len())
+                    .take_while(|&k| db2.hash_at(k) == h1).count() + j;
+                let names1: Vec<&str> = (i..i_end)
+                    .flat_map(|k| db1.names_at(k)).collect();
+                let names2: Vec<&str> = (j..j_end)
+                    .flat_map(|k| db2.names_at(k)).collect();
+                f(h1, &names1, &names2);
+                i = i_end;
+                j = j_end;
+            }
+        }
+    }
+}